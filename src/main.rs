@@ -5,12 +5,24 @@ use serde::{Serialize, Deserialize};
 use std::time::SystemTime;
 use std::path::Path;
 
-#[derive(Serialize, Deserialize)]
+#[cfg(feature = "stats")]
+mod stats;
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+enum TrackKind {
+    Track,
+    Episode
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 struct Metadata {
     title: String,
     artist: String,
+    kind: TrackKind,
+    art_url: Option<String>,
     length: i64,
     position: i64,
+    playback_status: String,
     timestamp: SystemTime
 }
 
@@ -33,21 +45,95 @@ trait Player {
         &self
     ) -> zbus::Result<()>;
     fn play_pause(&self) -> zbus::Result<()>;
+    fn seek(&self, offset: i64) -> zbus::Result<()>;
+    // Not wired to a CLI flag yet (needs a track_id the CLI has no good way
+    // to supply today), but kept for parity with spotifyd's D-Bus surface.
+    #[allow(dead_code)]
+    fn set_position(&self, track_id: zvariant::ObjectPath<'_>, position: i64) -> zbus::Result<()>;
     #[dbus_proxy(property)]
     fn position(&self) -> zbus::Result<i64>;
     #[dbus_proxy(property)]
     fn metadata(&self) -> zbus::Result<zvariant::Dict>;
     #[dbus_proxy(property)]
     fn playback_status(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn volume(&self) -> zbus::Result<f64>;
+    #[dbus_proxy(property)]
+    fn set_volume(&self, value: f64) -> zbus::Result<()>;
+    #[dbus_proxy(property)]
+    fn shuffle(&self) -> zbus::Result<bool>;
+    #[dbus_proxy(property)]
+    fn set_shuffle(&self, value: bool) -> zbus::Result<()>;
+    #[dbus_proxy(property)]
+    fn loop_status(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn set_loop_status(&self, value: String) -> zbus::Result<()>;
 }
 
-fn get_proxy<'a>() -> Result<PlayerProxy<'a>, Box<dyn Error>> {
-    let connection = zbus::Connection::new_session()?;
-    let spotify_bus = PlayerProxy::new(&connection)?;
+fn discover_player_service(connection: &zbus::Connection) -> Result<String, Box<dyn Error>> {
+    let dbus_proxy = zbus::fdo::DBusProxy::new(connection)?;
+    let mpris_names: Vec<String> = dbus_proxy
+        .list_names()?
+        .into_iter()
+        .filter(|name| name.starts_with("org.mpris.MediaPlayer2."))
+        .collect();
+
+    // Prefer spotifyd when it's among the running players, since that's
+    // still the common case this tool was built for.
+    if let Some(spotifyd) = mpris_names.iter().find(|name| name.as_str() == "org.mpris.MediaPlayer2.spotifyd") {
+        return Ok(spotifyd.clone());
+    }
+
+    mpris_names
+        .into_iter()
+        .next()
+        .ok_or_else(|| "no MPRIS player is running".into())
+}
+
+fn list_player_services(connection: &zbus::Connection) -> Result<Vec<String>, Box<dyn Error>> {
+    let dbus_proxy = zbus::fdo::DBusProxy::new(connection)?;
+
+    Ok(dbus_proxy
+        .list_names()?
+        .into_iter()
+        .filter(|name| name.starts_with("org.mpris.MediaPlayer2."))
+        .collect())
+}
+
+fn get_proxy<'a>(service: Option<&str>, connection: &'a zbus::Connection) -> Result<PlayerProxy<'a>, Box<dyn Error>> {
+    let service_name = match service {
+        Some(name) => String::from(name),
+        None => discover_player_service(connection)?
+    };
+
+    let spotify_bus = PlayerProxy::new_for(connection, &service_name, "/org/mpris/MediaPlayer2")?;
 
     Ok(spotify_bus)
 }
 
+fn parse_seek_offset(offset: &str) -> Result<i64, Box<dyn Error>> {
+    let seconds: f64 = offset.parse()?;
+
+    Ok((seconds * 1_000_000.0) as i64)
+}
+
+fn parse_loop_status(mode: &str) -> Result<String, Box<dyn Error>> {
+    match mode.to_lowercase().as_str() {
+        "none" => Ok(String::from("None")),
+        "track" => Ok(String::from("Track")),
+        "playlist" => Ok(String::from("Playlist")),
+        _ => Err(format!("invalid loop mode '{}', expected none/track/playlist", mode).into())
+    }
+}
+
+fn parse_on_off(value: &str) -> Result<bool, Box<dyn Error>> {
+    match value.to_lowercase().as_str() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("invalid value '{}', expected on/off", value).into())
+    }
+}
+
 fn get_cache() -> Result<Metadata, Box<dyn Error>> {
     let path = Path::new("/tmp/spotify_manage_cache");
     let cache_str = std::fs::read_to_string(path)?;
@@ -56,53 +142,225 @@ fn get_cache() -> Result<Metadata, Box<dyn Error>> {
     Ok(data)
 }
 
-fn get_metadata(proxy: Option<PlayerProxy>) -> Result<Metadata, Box<dyn Error>> {
+fn parse_track_kind(raw_metadata: &zvariant::Dict) -> Result<TrackKind, Box<dyn Error>> {
+    let url = raw_metadata.get::<str, str>("xesam:url")?;
+    let track_id = raw_metadata.get::<str, zvariant::ObjectPath>("mpris:trackid")?;
+
+    let identifier = url.or_else(|| track_id.map(|id| id.as_str())).unwrap_or("");
+
+    if identifier.contains(":episode:") {
+        Ok(TrackKind::Episode)
+    } else {
+        Ok(TrackKind::Track)
+    }
+}
+
+fn parse_track_metadata(raw_metadata: &zvariant::Dict) -> Result<(String, String, i64, TrackKind, Option<String>), Box<dyn Error>> {
+    let title = raw_metadata.get::<str, str>("xesam:title")?.ok_or("Invalid bus data")?;
+    let kind = parse_track_kind(raw_metadata)?;
+    let art_url = raw_metadata.get::<str, str>("mpris:artUrl")?.map(String::from);
+
+    let artist_array = raw_metadata.get::<str, zvariant::Array>("xesam:artist")?;
+
+    let artist = match artist_array {
+        Some(artists) if !artists.get().is_empty() => {
+            artists.get()[0].downcast_ref::<str>().ok_or("Invalid dbus data")?.to_string()
+        },
+        // Podcast episodes carry no xesam:artist, so fall back to the show
+        // name instead of failing the whole lookup.
+        _ => {
+            let album = raw_metadata.get::<str, str>("xesam:album")?;
+
+            let album_artist = raw_metadata.get::<str, zvariant::Array>("xesam:albumArtist")?
+                .and_then(|artists| artists.get().get(0).and_then(|value| value.downcast_ref::<str>()));
+
+            album.or(album_artist).ok_or("Invalid dbus data")?.to_string()
+        }
+    };
+
+    let length = *raw_metadata.get::<str, zvariant::Value>("mpris:length")?
+        .ok_or("Invalid dbus data")?
+        .downcast_ref::<i64>()
+        .ok_or("Invalid dbus data")?;
+
+    Ok((String::from(title), artist, length, kind, art_url))
+}
+
+fn cache_art(url: &str) -> Result<String, Box<dyn Error>> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    // Key the cached file by the art URL itself so a new track (a new URL)
+    // doesn't keep returning a previous track's stale cover.
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    let path = Path::new("/tmp").join(format!("spotify_manage_art_{:x}.jpg", hasher.finish()));
+
+    if !path.exists() {
+        let response = ureq::get(url).call()?;
+        let mut file = std::fs::File::create(&path)?;
+        std::io::copy(&mut response.into_reader(), &mut file)?;
+    }
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+fn get_art_url(service: Option<&str>, connection: &zbus::Connection) -> Result<Option<String>, Box<dyn Error>> {
+    let metadata = match get_metadata(None, service, connection) {
+        Ok(data) => data,
+        Err(_) => get_cache()?
+    };
+
+    Ok(metadata.art_url)
+}
+
+fn format_now_playing(title: &str, artist: &str, position: i64, length: i64, status: &str, kind: &TrackKind, art_url: Option<&str>, json: bool) -> String {
+    let kind_label = match kind {
+        TrackKind::Track => "track",
+        TrackKind::Episode => "episode"
+    };
+
+    if json {
+        serde_json::json!({
+            "title": title,
+            "artist": artist,
+            "position": position,
+            "length": length,
+            "status": status,
+            "kind": kind_label,
+            "art_url": art_url
+        }).to_string()
+    } else {
+        let progress = if length > 0 { position as f64 / length as f64 } else { 0.0 };
+
+        match art_url {
+            Some(url) => format!("{} - {} [{}] [{}] {:.2} {}", artist, title, status, kind_label, progress, url),
+            None => format!("{} - {} [{}] [{}] {:.2}", artist, title, status, kind_label, progress)
+        }
+    }
+}
+
+fn print_now_playing(player: &PlayerProxy, json: bool, last_track: &std::cell::RefCell<Option<(String, String)>>) -> Result<(), Box<dyn Error>> {
+    let raw_metadata = player.metadata()?;
+    let (title, artist, length, kind, art_url) = parse_track_metadata(&raw_metadata)?;
+    let position = player.position()?;
+    let status = player.playback_status()?;
+
+    // --watch is the long-running consumer stats is meant to feed. The
+    // now-playing hash reflects the live status on every refresh; only the
+    // play counter is deduped, once per track change rather than on every
+    // PropertiesChanged (e.g. a play/pause toggle).
+    #[cfg(feature = "stats")]
+    {
+        let _ = stats::record_now_playing(&title, &artist, &status);
+
+        let previous_track = last_track.borrow().clone();
+
+        if is_new_track(previous_track.as_ref(), &title, &artist) {
+            let _ = stats::record_play(&title, &artist);
+        }
+    }
+
+    *last_track.borrow_mut() = Some((title.clone(), artist.clone()));
+
+    println!("{}", format_now_playing(&title, &artist, position, length, &status, &kind, art_url.as_deref(), json));
+
+    Ok(())
+}
+
+fn watch(service: Option<&str>, json: bool, connection: zbus::Connection) -> Result<(), Box<dyn Error>> {
+    let service_name = match service {
+        Some(name) => String::from(name),
+        None => discover_player_service(&connection)?
+    };
+
+    let player = PlayerProxy::new_for(&connection, &service_name, "/org/mpris/MediaPlayer2")?;
+    let props_proxy = zbus::fdo::PropertiesProxy::new_for(&connection, &service_name, "/org/mpris/MediaPlayer2")?;
+    let last_track = std::cell::RefCell::new(None);
+
+    // Print the current state once so consumers don't have to wait for the
+    // first change before they have something to render.
+    print_now_playing(&player, json, &last_track)?;
+
+    let mut signal_receiver = zbus::SignalReceiver::new(connection)?;
+    signal_receiver.receive_for(&props_proxy)?;
+
+    props_proxy.connect_properties_changed(|_interface, changed, _invalidated| {
+        if changed.contains_key("Metadata") || changed.contains_key("PlaybackStatus") {
+            let _ = print_now_playing(&player, json, &last_track);
+        }
+
+        Ok(())
+    })?;
+
+    loop {
+        signal_receiver.wait_for_signal()?;
+    }
+}
+
+fn is_new_track(previous: Option<&(String, String)>, title: &str, artist: &str) -> bool {
+    previous
+        .map(|(last_title, last_artist)| last_title != title || last_artist != artist)
+        .unwrap_or(true)
+}
+
+fn get_metadata<'a>(proxy: Option<PlayerProxy<'a>>, service: Option<&str>, connection: &'a zbus::Connection) -> Result<Metadata, Box<dyn Error>> {
     let p_proxy = match proxy {
         Some (p) => p,
-        None => get_proxy()?
+        None => get_proxy(service, connection)?
     };
 
+    // Keep the last-known track around even once its cache entry goes
+    // stale, so a refresh can tell whether the track actually changed.
+    let previous_cache = get_cache().ok();
+
     // Check for metadata
-    let possible_data: Option<Metadata> = match get_cache() {
+    let possible_data: Option<Metadata> = match &previous_cache {
         // Cache data exists
-        Ok (cache_data) => if cache_data.timestamp.elapsed()?.as_secs() < 3 {
-            Some(cache_data)
+        Some (cache_data) => if cache_data.timestamp.elapsed()?.as_secs() < 3 {
+            Some(cache_data.clone())
         } else {
             None
         },
         // Cache data does not exist, get metadata from API
-        Err (_) => None
+        None => None
     };
 
     match possible_data {
         Some (data) => Ok(data),
         None => {
             let raw_metadata = p_proxy.metadata()?;
-
-            let title = raw_metadata.get::<str, str>("xesam:title")?.ok_or("Invalid bus data")?;
-            
-            let artist = raw_metadata.get::<str, zvariant::Array>("xesam:artist")?
-                .ok_or("Invalid dbus data")?
-                .get()[0]
-                .downcast_ref::<str>()
-                .ok_or("Invalid dbus data")?;
-
-            
-            let length = *raw_metadata.get::<str, zvariant::Value>("mpris:length")?
-                .ok_or("Invalid dbus data")?
-                .downcast_ref::<i64>()
-                .ok_or("Invalid dbus data")?;
-           
+            let (title, artist, length, kind, art_url) = parse_track_metadata(&raw_metadata)?;
             let position = p_proxy.position()?;
+            let playback_status = p_proxy.playback_status()?;
 
             let final_metadata = Metadata {
-                title: String::from(title),
-                artist: String::from(artist),
+                title,
+                artist,
+                kind,
+                art_url,
                 length,
                 position,
+                playback_status,
                 timestamp: SystemTime::now()
             };
 
+            // The now-playing hash reflects the live status on every
+            // refresh; only the play counter is deduped, once per track
+            // change rather than on every refresh a bar widget triggers.
+            // Best-effort: a dashboard being unreachable shouldn't stop
+            // playback commands from working.
+            #[cfg(feature = "stats")]
+            {
+                let _ = stats::record_now_playing(&final_metadata.title, &final_metadata.artist, &final_metadata.playback_status);
+
+                let previous_track = previous_cache.as_ref().map(|data| (data.title.clone(), data.artist.clone()));
+
+                if is_new_track(previous_track.as_ref(), &final_metadata.title, &final_metadata.artist) {
+                    let _ = stats::record_play(&final_metadata.title, &final_metadata.artist);
+                }
+            }
+
             let meta_as_string = serde_json::to_string(&final_metadata)?;
             let meta_as_bytes = meta_as_string.into_bytes();
 
@@ -115,20 +373,25 @@ fn get_metadata(proxy: Option<PlayerProxy>) -> Result<Metadata, Box<dyn Error>>
     }
 }
 
-fn get_song_progress() -> Result<f64, Box<dyn Error>> {
-    let metadata = get_metadata(None)?;
-    let current_pos = metadata.position as f64;
+fn get_song_progress(service: Option<&str>, connection: &zbus::Connection) -> Result<f64, Box<dyn Error>> {
+    let metadata = get_metadata(None, service, connection)?;
     let song_length = metadata.length as f64;
 
+    let current_pos = if metadata.playback_status == "Playing" {
+        let estimated = metadata.position + metadata.timestamp.elapsed()?.as_micros() as i64;
+        estimated.min(metadata.length) as f64
+    } else {
+        metadata.position as f64
+    };
 
     Ok(current_pos / song_length)
 }
 
-fn get_song_name() -> Result<String, Box<dyn Error>> {
-    let metadata = match get_metadata(None) {
+fn get_song_name(service: Option<&str>, connection: &zbus::Connection) -> Result<String, Box<dyn Error>> {
+    let metadata = match get_metadata(None, service, connection) {
         Ok(data) => data,
         Err(_) => get_cache()?
-    }; 
+    };
     let artist = metadata.artist;
     let song_name = metadata.title;
 
@@ -151,13 +414,56 @@ struct Opt {
     #[structopt(long)]
     status: bool,
     #[structopt(long)]
-    playpause: bool
+    playpause: bool,
+    /// Control a specific MPRIS player, e.g. org.mpris.MediaPlayer2.vlc
+    #[structopt(long)]
+    player: Option<String>,
+    /// List the MPRIS-capable players currently running and exit
+    #[structopt(long)]
+    list_players: bool,
+    /// Seek by an offset in seconds, e.g. --seek +10 or --seek -5
+    #[structopt(long, allow_hyphen_values = true)]
+    seek: Option<String>,
+    /// Set the playback volume (0.0-1.0)
+    #[structopt(long)]
+    volume: Option<f64>,
+    /// Turn shuffle on or off
+    #[structopt(long)]
+    shuffle: Option<String>,
+    /// Set the loop mode: none, track or playlist
+    #[structopt(long = "loop")]
+    loop_mode: Option<String>,
+    /// Keep running and print a line each time the track or playback status changes
+    #[structopt(long)]
+    watch: bool,
+    /// Print --watch output as JSON; other output is always plain text
+    #[structopt(long)]
+    json: bool,
+    /// Print the URL of the current track's album art
+    #[structopt(long)]
+    art: bool,
+    /// Used with --art: download the art once into /tmp and print that path instead of the URL
+    #[structopt(long = "cache-art")]
+    cache_art: bool
 }
 
 fn main() -> Result<(), Box<dyn Error>>{
     let opt = Opt::from_args();
     let connection = zbus::Connection::new_session()?;
-    let player = PlayerProxy::new(&connection)?;
+
+    if opt.list_players {
+        for name in list_player_services(&connection)? {
+            println!("{}", name)
+        }
+
+        return Ok(());
+    }
+
+    if opt.watch {
+        return watch(opt.player.as_deref(), opt.json, connection);
+    }
+
+    let player = get_proxy(opt.player.as_deref(), &connection)?;
 
     if opt.play {
         player.play()?
@@ -172,11 +478,11 @@ fn main() -> Result<(), Box<dyn Error>>{
     }
 
     if opt.progress {
-        println!("{}", get_song_progress()?)
+        println!("{}", get_song_progress(opt.player.as_deref(), &connection)?)
     }
 
     if opt.song {
-        println!("{}", get_song_name()?)
+        println!("{}", get_song_name(opt.player.as_deref(), &connection)?)
     }
 
     if opt.status {
@@ -186,6 +492,30 @@ fn main() -> Result<(), Box<dyn Error>>{
     if opt.playpause {
         player.play_pause()?
     }
-    
+
+    if let Some(offset) = &opt.seek {
+        player.seek(parse_seek_offset(offset)?)?
+    }
+
+    if let Some(volume) = opt.volume {
+        player.set_volume(volume)?
+    }
+
+    if let Some(shuffle) = &opt.shuffle {
+        player.set_shuffle(parse_on_off(shuffle)?)?
+    }
+
+    if let Some(loop_mode) = &opt.loop_mode {
+        player.set_loop_status(parse_loop_status(loop_mode)?)?
+    }
+
+    if opt.art {
+        match get_art_url(opt.player.as_deref(), &connection)? {
+            Some(url) if opt.cache_art => println!("{}", cache_art(&url)?),
+            Some(url) => println!("{}", url),
+            None => return Err("no album art available for the current track".into())
+        }
+    }
+
     Ok(())
 }
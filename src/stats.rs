@@ -0,0 +1,33 @@
+use std::error::Error;
+use redis::Commands;
+
+const NOW_PLAYING_KEY: &str = "spotify_manage:now_playing";
+const PLAY_COUNTS_KEY: &str = "spotify_manage:play_counts";
+
+fn connect() -> Result<redis::Connection, Box<dyn Error>> {
+    let redis_url = std::env::var("SPOTIFY_MANAGE_REDIS_URL")?;
+    let client = redis::Client::open(redis_url)?;
+
+    Ok(client.get_connection()?)
+}
+
+pub fn record_now_playing(title: &str, artist: &str, status: &str) -> Result<(), Box<dyn Error>> {
+    let mut con = connect()?;
+
+    con.hset_multiple(NOW_PLAYING_KEY, &[
+        ("title", title),
+        ("artist", artist),
+        ("status", status)
+    ])?;
+
+    Ok(())
+}
+
+pub fn record_play(title: &str, artist: &str) -> Result<(), Box<dyn Error>> {
+    let mut con = connect()?;
+    let track_key = format!("{} - {}", artist, title);
+
+    con.zincr(PLAY_COUNTS_KEY, track_key, 1)?;
+
+    Ok(())
+}